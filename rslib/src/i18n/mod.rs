@@ -1,17 +1,26 @@
 // Copyright: Ankitects Pty Ltd and contributors
 // License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
 
-use crate::err::Result;
+use crate::err::{AnkiError, Result};
 use crate::log::{error, Logger};
-use fluent::{FluentArgs, FluentBundle, FluentResource, FluentValue};
+use blake3::Hasher;
+use fluent::{FluentArgs, FluentBundle, FluentError, FluentMessage, FluentResource, FluentValue};
+use fluent_syntax::ast::{
+    CallArguments, Entry, Expression, InlineExpression, Pattern, PatternElement, ResourceEntry,
+    VariantKey,
+};
 use intl_memoizer::IntlLangMemoizer;
+use intl_pluralrules::{PluralCategory, PluralRuleType, PluralRules};
 use num_format::Locale;
 use serde::Serialize;
 use std::borrow::Cow;
+use std::collections::HashSet;
 use std::fs;
+use std::io::Read;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
-use unic_langid::LanguageIdentifier;
+use unic_langid::{langid, CharacterDirection, LanguageIdentifier};
 
 mod autogen;
 use crate::i18n::autogen::FLUENT_KEYS;
@@ -40,7 +49,18 @@ pub use tr_strs;
 /// If neither folder exists, return None.
 fn lang_folder(lang: Option<&LanguageIdentifier>, ftl_folder: &Path) -> Option<PathBuf> {
     if let Some(lang) = lang {
-        if let Some(region) = lang.region() {
+        // Chinese folders are named by region, but picked by script like
+        // the embedded translations
+        let region = if lang.language() == "zh" {
+            Some(if uses_traditional_chinese(lang) {
+                "TW"
+            } else {
+                "CN"
+            })
+        } else {
+            lang.region()
+        };
+        if let Some(region) = region {
             let path = ftl_folder.join(format!("{}_{}", lang.language(), region));
             if fs::metadata(&path).is_ok() {
                 return Some(path);
@@ -63,12 +83,46 @@ fn lang_folder(lang: Option<&LanguageIdentifier>, ftl_folder: &Path) -> Option<P
     }
 }
 
+/// Parse a locale code into an identifier. LanguageIdentifier does not
+/// support BCP-47 extensions, so if the code can't be parsed as-is, any
+/// Unicode (-u-), private use (-x-) or other singleton-introduced
+/// subtags are dropped, so eg ja-JP-u-ca-japanese is treated as ja-JP.
+/// The dropped subtags can be recovered with lang_extensions().
+fn parse_lang(code: &str) -> Option<LanguageIdentifier> {
+    if let Ok(lang) = code.parse() {
+        return Some(lang);
+    }
+    let base: Vec<_> = code
+        .split(|c| c == '-' || c == '_')
+        .take_while(|subtag| subtag.len() > 1)
+        .collect();
+    base.join("-").parse().ok()
+}
+
+/// The extension subtags of a locale code, starting at the first
+/// singleton, eg "u-ca-japanese" for ja-JP-u-ca-japanese.
+fn lang_extensions(code: &str) -> Option<String> {
+    let subtags: Vec<_> = code.split(|c| c == '-' || c == '_').collect();
+    let start = subtags.iter().position(|subtag| subtag.len() == 1)?;
+    Some(subtags[start..].join("-"))
+}
+
 /// Get the template/English resource text for the given group.
 /// These are embedded in the binary.
 fn ftl_template_text() -> &'static str {
     include_str!("ftl/template.ftl")
 }
 
+/// Whether a Chinese language should use traditional characters. An
+/// explicit script takes precedence over the region, so eg zh-Hant can be
+/// used to get traditional characters.
+fn uses_traditional_chinese(lang: &LanguageIdentifier) -> bool {
+    match (lang.script(), lang.region()) {
+        (Some("Hant"), _) | (None, Some("TW")) | (None, Some("HK")) => true,
+        _ => false,
+    }
+}
+
 fn ftl_localized_text(lang: &LanguageIdentifier) -> Option<&'static str> {
     Some(match lang.language() {
         "en" => {
@@ -78,10 +132,13 @@ fn ftl_localized_text(lang: &LanguageIdentifier) -> Option<&'static str> {
                 _ => return None,
             }
         }
-        "zh" => match lang.region() {
-            Some("TW") | Some("HK") => include_str!("ftl/zh-TW.ftl"),
-            _ => include_str!("ftl/zh-CN.ftl"),
-        },
+        "zh" => {
+            if uses_traditional_chinese(lang) {
+                include_str!("ftl/zh-TW.ftl")
+            } else {
+                include_str!("ftl/zh-CN.ftl")
+            }
+        }
         "pt" => {
             if let Some("PT") = lang.region() {
                 include_str!("ftl/pt-PT.ftl")
@@ -160,10 +217,15 @@ fn ftl_external_text(folder: &Path) -> Result<String> {
 /// extra_text may contain resources loaded from the filesystem
 /// at runtime. If it contains errors, they will not prevent a
 /// bundle from being returned.
+/// The ids of any parsed messages are added to keys, and the ids of
+/// any terms to terms.
 fn get_bundle(
     text: &str,
     extra_text: String,
     locales: &[LanguageIdentifier],
+    group_numbers: &Arc<AtomicBool>,
+    keys: &mut HashSet<String>,
+    terms: &mut HashSet<String>,
     log: &Logger,
 ) -> Option<FluentBundle<FluentResource>> {
     let res = FluentResource::try_new(text.into())
@@ -171,6 +233,8 @@ fn get_bundle(
             error!(log, "Unable to parse translations file: {:?}", e);
         })
         .ok()?;
+    keys.extend(message_ids(&res));
+    terms.extend(term_ids(&res));
 
     let mut bundle: FluentBundle<FluentResource> = FluentBundle::new(locales);
     bundle
@@ -182,7 +246,11 @@ fn get_bundle(
 
     if !extra_text.is_empty() {
         match FluentResource::try_new(extra_text) {
-            Ok(res) => bundle.add_resource_overriding(res),
+            Ok(res) => {
+                keys.extend(message_ids(&res));
+                terms.extend(term_ids(&res));
+                bundle.add_resource_overriding(res);
+            }
             Err((_res, e)) => error!(log, "Unable to parse translations file: {:?}", e),
         }
     }
@@ -193,7 +261,7 @@ fn get_bundle(
     }
 
     // add numeric formatter
-    set_bundle_formatter_for_langs(&mut bundle, locales);
+    set_bundle_formatter_for_langs(&mut bundle, locales, group_numbers.clone());
 
     Some(bundle)
 }
@@ -204,6 +272,9 @@ fn get_bundle_with_extra(
     lang: Option<&LanguageIdentifier>,
     ftl_folder: &Path,
     locales: &[LanguageIdentifier],
+    group_numbers: &Arc<AtomicBool>,
+    keys: &mut HashSet<String>,
+    terms: &mut HashSet<String>,
     log: &Logger,
 ) -> Option<FluentBundle<FluentResource>> {
     let extra_text = if let Some(path) = lang_folder(lang, &ftl_folder) {
@@ -218,7 +289,453 @@ fn get_bundle_with_extra(
         "".into()
     };
 
-    get_bundle(text, extra_text, locales, log)
+    get_bundle(text, extra_text, locales, group_numbers, keys, terms, log)
+}
+
+/// The ids of the messages defined in a resource.
+fn message_ids(res: &FluentResource) -> impl Iterator<Item = String> + '_ {
+    res.ast().body.iter().filter_map(|entry| match entry {
+        ResourceEntry::Entry(Entry::Message(msg)) => Some(msg.id.name.to_string()),
+        _ => None,
+    })
+}
+
+/// The ids of the terms defined in a resource, without their leading '-'.
+fn term_ids(res: &FluentResource) -> impl Iterator<Item = String> + '_ {
+    res.ast().body.iter().filter_map(|entry| match entry {
+        ResourceEntry::Entry(Entry::Term(term)) => Some(term.id.name.to_string()),
+        _ => None,
+    })
+}
+
+/// The names of the variables referenced by a message's value and
+/// attributes, including those only used by messages it references.
+fn message_variables<'a>(
+    bundle: &'a FluentBundle<FluentResource>,
+    msg: &FluentMessage<'a>,
+) -> HashSet<&'a str> {
+    let mut walker = VariableWalker {
+        bundle,
+        vars: HashSet::new(),
+        seen: HashSet::new(),
+    };
+    if let Some(pat) = msg.value {
+        walker.pattern(pat);
+    }
+    for pat in msg.attributes.values() {
+        walker.pattern(pat);
+    }
+    walker.vars
+}
+
+struct VariableWalker<'a> {
+    // the bundle message references are resolved in
+    bundle: &'a FluentBundle<FluentResource>,
+    vars: HashSet<&'a str>,
+    // message references already followed, so cycles terminate
+    seen: HashSet<(&'a str, Option<&'a str>)>,
+}
+
+impl<'a> VariableWalker<'a> {
+    fn pattern(&mut self, pat: &Pattern<'a>) {
+        for elem in &pat.elements {
+            if let PatternElement::Placeable(expr) = elem {
+                self.expression(expr);
+            }
+        }
+    }
+
+    fn expression(&mut self, expr: &Expression<'a>) {
+        match expr {
+            Expression::InlineExpression(inline) => self.inline(inline),
+            Expression::SelectExpression { selector, variants } => {
+                self.inline(selector);
+                for variant in variants {
+                    self.pattern(&variant.value);
+                }
+            }
+        }
+    }
+
+    fn inline(&mut self, expr: &InlineExpression<'a>) {
+        match expr {
+            InlineExpression::VariableReference { id } => {
+                self.vars.insert(id.name);
+            }
+            InlineExpression::MessageReference { id, attribute } => {
+                self.message_reference(id.name, attribute.as_ref().map(|attr| attr.name))
+            }
+            InlineExpression::FunctionReference {
+                arguments: Some(args),
+                ..
+            }
+            | InlineExpression::TermReference {
+                arguments: Some(args),
+                ..
+            } => {
+                for arg in &args.positional {
+                    self.inline(arg);
+                }
+                for arg in &args.named {
+                    self.inline(&arg.value);
+                }
+            }
+            InlineExpression::Placeable { expression } => self.expression(expression),
+            _ => (),
+        }
+    }
+
+    fn message_reference(&mut self, id: &'a str, attribute: Option<&'a str>) {
+        if !self.seen.insert((id, attribute)) {
+            return;
+        }
+        if let Some(msg) = self.bundle.get_message(id) {
+            let pat = match attribute {
+                Some(attr) => msg.attributes.get(attr).copied(),
+                None => msg.value,
+            };
+            if let Some(pat) = pat {
+                self.pattern(pat);
+            }
+        }
+    }
+}
+
+/// The identifiers used as selector variant keys in a message, such as
+/// "one" and "other".
+fn message_variant_keys<'a>(msg: &FluentMessage<'a>) -> HashSet<&'a str> {
+    let mut keys = HashSet::new();
+    if let Some(pat) = msg.value {
+        pattern_variant_keys(pat, &mut keys);
+    }
+    for pat in msg.attributes.values() {
+        pattern_variant_keys(pat, &mut keys);
+    }
+    keys
+}
+
+fn pattern_variant_keys<'a>(pat: &Pattern<'a>, keys: &mut HashSet<&'a str>) {
+    for elem in &pat.elements {
+        if let PatternElement::Placeable(expr) = elem {
+            expression_variant_keys(expr, keys);
+        }
+    }
+}
+
+fn expression_variant_keys<'a>(expr: &Expression<'a>, keys: &mut HashSet<&'a str>) {
+    match expr {
+        Expression::InlineExpression(InlineExpression::Placeable { expression }) => {
+            expression_variant_keys(expression, keys)
+        }
+        Expression::InlineExpression(_) => (),
+        Expression::SelectExpression { variants, .. } => {
+            for variant in variants {
+                if let VariantKey::Identifier { name } = &variant.key {
+                    keys.insert(*name);
+                }
+                pattern_variant_keys(&variant.value, keys);
+            }
+        }
+    }
+}
+
+/// The names of the terms referenced by a message's value and attributes,
+/// without their leading '-'.
+fn message_term_references<'a>(msg: &FluentMessage<'a>) -> HashSet<&'a str> {
+    let mut terms = HashSet::new();
+    if let Some(pat) = msg.value {
+        pattern_term_references(pat, &mut terms);
+    }
+    for pat in msg.attributes.values() {
+        pattern_term_references(pat, &mut terms);
+    }
+    terms
+}
+
+fn pattern_term_references<'a>(pat: &Pattern<'a>, terms: &mut HashSet<&'a str>) {
+    for elem in &pat.elements {
+        if let PatternElement::Placeable(expr) = elem {
+            expression_term_references(expr, terms);
+        }
+    }
+}
+
+fn expression_term_references<'a>(expr: &Expression<'a>, terms: &mut HashSet<&'a str>) {
+    match expr {
+        Expression::InlineExpression(inline) => inline_term_references(inline, terms),
+        Expression::SelectExpression { selector, variants } => {
+            inline_term_references(selector, terms);
+            for variant in variants {
+                pattern_term_references(&variant.value, terms);
+            }
+        }
+    }
+}
+
+fn inline_term_references<'a>(expr: &InlineExpression<'a>, terms: &mut HashSet<&'a str>) {
+    if let InlineExpression::TermReference { id, .. } = expr {
+        terms.insert(id.name);
+    }
+    match expr {
+        InlineExpression::FunctionReference {
+            arguments: Some(args),
+            ..
+        }
+        | InlineExpression::TermReference {
+            arguments: Some(args),
+            ..
+        } => {
+            for arg in &args.positional {
+                inline_term_references(arg, terms);
+            }
+            for arg in &args.named {
+                inline_term_references(&arg.value, terms);
+            }
+        }
+        InlineExpression::Placeable { expression } => expression_term_references(expression, terms),
+        _ => (),
+    }
+}
+
+/// Parse ftl text passed in by the caller, failing if any of it is invalid.
+fn parse_patch(patch_ftl: &str) -> Result<FluentResource> {
+    FluentResource::try_new(patch_ftl.into()).map_err(|(_res, e)| {
+        AnkiError::invalid_input(format!("unable to parse translations: {:?}", e))
+    })
+}
+
+/// The primary and nested (open, close) quotation marks of a language,
+/// from CLDR. Unlisted languages use the English ones.
+fn quotation_marks(lang: &LanguageIdentifier) -> [(&'static str, &'static str); 2] {
+    match lang.language() {
+        "fr" => [("«", "»"), ("«", "»")],
+        "de" => [("„", "“"), ("‚", "‘")],
+        "es" | "it" => [("«", "»"), ("“", "”")],
+        "ru" | "uk" => [("«", "»"), ("„", "“")],
+        "pl" => [("„", "”"), ("«", "»")],
+        "ja" => [("「", "」"), ("『", "』")],
+        // simplified Chinese uses the default marks
+        "zh" if uses_traditional_chinese(lang) => [("「", "」"), ("『", "』")],
+        _ => [("“", "”"), ("‘", "’")],
+    }
+}
+
+/// True if a pattern can only produce an empty string, eg { "" }.
+fn pattern_is_empty(pat: &Pattern) -> bool {
+    pat.elements.iter().all(|elem| match elem {
+        PatternElement::TextElement(text) => text.is_empty(),
+        PatternElement::Placeable(Expression::InlineExpression(
+            InlineExpression::StringLiteral { value },
+        )) => value.is_empty(),
+        _ => false,
+    })
+}
+
+/// A canonical text form of a message's value and attributes, similar to
+/// its ftl source. Unlike the AST's Debug output, it only changes when the
+/// message does, not when fluent-syntax is upgraded.
+fn message_source(msg: &FluentMessage) -> String {
+    let mut buf = String::new();
+    if let Some(pat) = msg.value {
+        write_pattern(&mut buf, pat);
+    }
+    let mut attributes: Vec<_> = msg.attributes.iter().collect();
+    attributes.sort_unstable_by_key(|(name, _)| **name);
+    for (name, pat) in attributes {
+        buf.push_str("\n.");
+        buf.push_str(name);
+        buf.push_str(" = ");
+        write_pattern(&mut buf, pat);
+    }
+    buf
+}
+
+fn write_pattern(buf: &mut String, pat: &Pattern) {
+    for elem in &pat.elements {
+        match elem {
+            PatternElement::TextElement(text) => buf.push_str(text),
+            PatternElement::Placeable(expr) => {
+                buf.push('{');
+                write_expression(buf, expr);
+                buf.push('}');
+            }
+        }
+    }
+}
+
+fn write_expression(buf: &mut String, expr: &Expression) {
+    match expr {
+        Expression::InlineExpression(inline) => write_inline(buf, inline),
+        Expression::SelectExpression { selector, variants } => {
+            write_inline(buf, selector);
+            buf.push_str(" ->");
+            for variant in variants {
+                buf.push_str(if variant.default { " *[" } else { " [" });
+                match &variant.key {
+                    VariantKey::Identifier { name } => buf.push_str(name),
+                    VariantKey::NumberLiteral { value } => buf.push_str(value),
+                }
+                buf.push_str("] ");
+                write_pattern(buf, &variant.value);
+            }
+        }
+    }
+}
+
+fn write_inline(buf: &mut String, expr: &InlineExpression) {
+    match expr {
+        InlineExpression::StringLiteral { value } => {
+            buf.push('"');
+            buf.push_str(value);
+            buf.push('"');
+        }
+        InlineExpression::NumberLiteral { value } => buf.push_str(value),
+        InlineExpression::FunctionReference { id, arguments } => {
+            buf.push_str(id.name);
+            write_arguments(buf, arguments.as_ref());
+        }
+        InlineExpression::MessageReference { id, attribute } => {
+            buf.push_str(id.name);
+            if let Some(attr) = attribute {
+                buf.push('.');
+                buf.push_str(attr.name);
+            }
+        }
+        InlineExpression::TermReference {
+            id,
+            attribute,
+            arguments,
+        } => {
+            buf.push('-');
+            buf.push_str(id.name);
+            if let Some(attr) = attribute {
+                buf.push('.');
+                buf.push_str(attr.name);
+            }
+            write_arguments(buf, arguments.as_ref());
+        }
+        InlineExpression::VariableReference { id } => {
+            buf.push('$');
+            buf.push_str(id.name);
+        }
+        InlineExpression::Placeable { expression } => {
+            buf.push('{');
+            write_expression(buf, expression);
+            buf.push('}');
+        }
+    }
+}
+
+fn write_arguments(buf: &mut String, args: Option<&CallArguments>) {
+    let args = match args {
+        Some(args) => args,
+        None => return,
+    };
+    buf.push('(');
+    for (idx, arg) in args.positional.iter().enumerate() {
+        if idx > 0 {
+            buf.push_str(", ");
+        }
+        write_inline(buf, arg);
+    }
+    for (idx, arg) in args.named.iter().enumerate() {
+        if idx > 0 || !args.positional.is_empty() {
+            buf.push_str(", ");
+        }
+        buf.push_str(arg.name.name);
+        buf.push_str(": ");
+        write_inline(buf, &arg.value);
+    }
+    buf.push(')');
+}
+
+/// The length of a pattern's literal text, ignoring any placeables.
+fn pattern_text_len(pat: &Pattern) -> usize {
+    pat.elements
+        .iter()
+        .map(|elem| match elem {
+            PatternElement::TextElement(text) => text.len(),
+            PatternElement::Placeable(_) => 0,
+        })
+        .sum()
+}
+
+/// The approximate size of a message in bundle, if it has one. Only the
+/// key and literal text are counted.
+fn message_size(bundle: &FluentBundle<FluentResource>, key: &str) -> Option<usize> {
+    bundle.get_message(key).map(|msg| {
+        let attrs: usize = msg
+            .attributes
+            .values()
+            .map(|pat| pattern_text_len(pat))
+            .sum();
+        key.len() + msg.value.map(pattern_text_len).unwrap_or_default() + attrs
+    })
+}
+
+/// Names of any string args containing U+FFFD, which usually means the
+/// caller decoded its text lossily. Returned in sorted order.
+fn args_with_replacement_chars<'a>(args: &FluentArgs<'a>) -> Vec<&'a str> {
+    let mut names: Vec<_> = args
+        .iter()
+        .filter_map(|(name, val)| match val {
+            FluentValue::String(s) if s.contains('\u{FFFD}') => Some(*name),
+            _ => None,
+        })
+        .collect();
+    names.sort_unstable();
+    names
+}
+
+/// Names of any args the message doesn't reference, which usually means a
+/// variable was renamed without updating the caller. Returned in sorted order.
+fn unused_args<'a>(
+    bundle: &FluentBundle<FluentResource>,
+    msg: &FluentMessage,
+    args: &FluentArgs<'a>,
+) -> Vec<&'a str> {
+    let vars = message_variables(bundle, msg);
+    let mut names: Vec<_> = args
+        .keys()
+        .filter(|name| !vars.contains(*name))
+        .copied()
+        .collect();
+    names.sort_unstable();
+    names
+}
+
+/// Where a translation will be presented.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TrContext {
+    /// Shown on screen; uses the message's value.
+    Visual,
+    /// Read aloud by a screen reader; uses the message's .voice attribute
+    /// if it has one, as the on-screen text may be too terse.
+    Voice,
+}
+
+impl TrContext {
+    fn attribute(self) -> Option<&'static str> {
+        match self {
+            TrContext::Visual => None,
+            TrContext::Voice => Some("voice"),
+        }
+    }
+}
+
+/// A translation lookup, as reported to the callback passed to
+/// I18n::set_trace().
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceEvent {
+    pub key: String,
+    /// The language that provided the text, or None for the English
+    /// template or a missing key.
+    pub lang: Option<LanguageIdentifier>,
+    /// True if the first language consulted didn't provide the text.
+    pub fallback: bool,
+    /// False if no language had the key.
+    pub found: bool,
+    pub errors: Vec<String>,
 }
 
 #[derive(Clone)]
@@ -237,30 +754,50 @@ impl I18n {
         let mut langs = vec![];
         let mut bundles = Vec::with_capacity(locale_codes.len() + 1);
         let mut resource_text = vec![];
+        let mut bundle_langs = vec![];
+        let mut keys = HashSet::new();
+        let mut bundle_terms = vec![];
+        let mut rejected_codes = vec![];
+        let mut extensions = vec![];
 
         for code in locale_codes {
             let code = code.as_ref();
-            if let Ok(lang) = code.parse::<LanguageIdentifier>() {
+            if let Some(lang) = parse_lang(code) {
                 langs.push(lang.clone());
+                extensions.push(lang_extensions(code));
                 if lang.language() == "en" {
                     // if English was listed, any further preferences are skipped,
                     // as the template has 100% coverage, and we need to ensure
                     // it is tried prior to any other langs.
                     break;
                 }
+            } else if !code.is_empty() {
+                rejected_codes.push(code.to_string());
             }
         }
         // add fallback date/time
         langs.push("en_US".parse().unwrap());
+        extensions.push(None);
+        let group_numbers = Arc::new(AtomicBool::new(false));
 
         for lang in &langs {
             // if the language is bundled in the binary
             if let Some(text) = ftl_localized_text(lang) {
-                if let Some(bundle) =
-                    get_bundle_with_extra(text, Some(lang), &ftl_folder, &langs, &log)
-                {
+                let mut terms = HashSet::new();
+                if let Some(bundle) = get_bundle_with_extra(
+                    text,
+                    Some(lang),
+                    &ftl_folder,
+                    &langs,
+                    &group_numbers,
+                    &mut keys,
+                    &mut terms,
+                    &log,
+                ) {
                     resource_text.push(text);
                     bundles.push(bundle);
+                    bundle_langs.push(Some(lang.clone()));
+                    bundle_terms.push(terms);
                 } else {
                     error!(log, "Failed to create bundle for {:?}", lang.language())
                 }
@@ -269,27 +806,112 @@ impl I18n {
 
         // add English templates
         let template_text = ftl_template_text();
-        let template_bundle =
-            get_bundle_with_extra(template_text, None, &ftl_folder, &langs, &log).unwrap();
+        let mut terms = HashSet::new();
+        let template_bundle = get_bundle_with_extra(
+            template_text,
+            None,
+            &ftl_folder,
+            &langs,
+            &group_numbers,
+            &mut keys,
+            &mut terms,
+            &log,
+        )
+        .unwrap();
         resource_text.push(template_text);
         bundles.push(template_bundle);
+        bundle_langs.push(None);
+        bundle_terms.push(terms);
 
+        let runtime_text = vec![vec![]; bundles.len()];
         Self {
             inner: Arc::new(Mutex::new(I18nInner {
                 bundles,
+                bundle_langs,
+                keys,
+                bundle_terms,
                 langs,
                 resource_text,
+                ftl_folder,
+                rejected_codes,
+                extensions,
+                group_numbers,
+                replaced_bytes: 0,
+                trace: None,
+                runtime_text,
             })),
             log,
         }
     }
 
+    /// Like new(), but returns an error if some locale codes were rejected,
+    /// none could be parsed, and the ftl folder is missing or empty, as
+    /// nothing but the embedded English text could ever be shown. Falling
+    /// back on English for an unknown but valid code, or because no
+    /// preference was given, still succeeds.
+    pub fn try_new<S: AsRef<str>, P: Into<PathBuf>>(
+        locale_codes: &[S],
+        ftl_folder: P,
+        log: Logger,
+    ) -> Result<Self> {
+        let i18n = Self::new(locale_codes, ftl_folder, log);
+        let unusable = {
+            let inner = i18n.inner.lock().unwrap();
+            // langs always ends with the en-US fallback
+            !inner.rejected_codes.is_empty() && inner.langs.len() == 1
+        };
+        if unusable {
+            i18n.check_folder()?;
+        }
+        Ok(i18n)
+    }
+
+    /// Describe how new() would treat each of the provided locale codes,
+    /// one per line, to help diagnose why the UI is in an unexpected
+    /// language.
+    pub fn explain_negotiation<S: AsRef<str>>(locale_codes: &[S]) -> String {
+        let mut lines = vec![];
+        for code in locale_codes {
+            let code = code.as_ref();
+            if code.is_empty() {
+                continue;
+            }
+            let lang = match parse_lang(code) {
+                Some(lang) => lang,
+                None => {
+                    lines.push(format!("requested {} → could not be parsed, ignored", code));
+                    continue;
+                }
+            };
+            if ftl_localized_text(&lang).is_some() {
+                lines.push(format!("requested {} → translation for {}", code, lang));
+            } else if lang.language() == "en" {
+                lines.push(format!("requested {} → English", code));
+            } else {
+                lines.push(format!("requested {} → no translation, skipped", code));
+            }
+            if lang.language() == "en" {
+                lines.push("any further languages are ignored".to_string());
+                break;
+            }
+        }
+        lines.push("anything untranslated will use English".to_string());
+
+        lines.join("\n")
+    }
+
     /// Get translation with zero arguments.
     pub fn tr(&self, key: TR) -> Cow<str> {
         let key = FLUENT_KEYS[key as usize];
         self.tr_(key, None)
     }
 
+    /// Get translation with zero arguments, and the direction it should be
+    /// laid out in.
+    pub fn tr_with_dir(&self, key: TR) -> (Cow<str>, CharacterDirection) {
+        (self.tr(key), self.text_direction())
+    }
+
     /// Get translation with one or more arguments.
     pub fn trn(&self, key: TR, args: FluentArgs) -> String {
         let key = FLUENT_KEYS[key as usize];
@@ -297,102 +919,910 @@ impl I18n {
     }
 
     fn tr_<'a>(&'a self, key: &str, args: Option<FluentArgs>) -> Cow<'a, str> {
-        for bundle in &self.inner.lock().unwrap().bundles {
+        if let Some(args) = &args {
+            let lossy = args_with_replacement_chars(args);
+            if !lossy.is_empty() {
+                error!(
+                    self.log,
+                    "Replacement character in arg(s) {:?} of '{}'", lossy, key
+                );
+            }
+        }
+
+        let inner = self.inner.lock().unwrap();
+
+        if cfg!(debug_assertions) {
+            if let Some(args) = &args {
+                // translations may legitimately omit a variable, so only
+                // the template is checked
+                let template = inner.bundles.last().unwrap();
+                if let Some(msg) = template.get_message(key) {
+                    let unused = unused_args(template, &msg, args);
+                    if !unused.is_empty() {
+                        error!(self.log, "Unused arg(s) {:?} passed to '{}'", unused, key);
+                    }
+                }
+            }
+        }
+
+        let formatted = self.format_with_first_at(&inner.bundles, key, None, args.as_ref());
+
+        if let Some(trace) = inner.trace.clone() {
+            let event = TraceEvent {
+                key: key.to_string(),
+                lang: formatted
+                    .as_ref()
+                    .and_then(|(idx, ..)| inner.bundle_langs[*idx].clone()),
+                fallback: formatted.as_ref().map(|(idx, ..)| *idx > 0).unwrap_or(true),
+                found: formatted.is_some(),
+                errors: formatted
+                    .as_ref()
+                    .map(|(_, _, errs)| errs.iter().map(|err| format!("{:?}", err)).collect())
+                    .unwrap_or_default(),
+            };
+            // the callback may use this I18n
+            drop(inner);
+            trace(event);
+        }
+
+        formatted
+            .map(|(_, text, _)| text)
+            // return the key name if it was missing
+            .unwrap_or_else(|| key.to_string())
+            .into()
+    }
+
+    /// Call the provided function for each translation looked up by tr()
+    /// and trn(), for diagnosing an unexpected string. It is heavier than
+    /// normal lookups, so is intended for short debugging sessions. Pass
+    /// None to stop tracing.
+    pub fn set_trace(&self, trace: Option<Box<dyn Fn(TraceEvent) + Send + Sync>>) {
+        self.inner.lock().unwrap().trace = trace.map(Arc::from);
+    }
+
+    /// Format key with the first of the provided bundles that has a value
+    /// for it, logging any errors. Returns None if no bundle has the key.
+    /// If an attribute is provided, it is used in place of the value in
+    /// bundles that define it.
+    fn format_with_first<'b>(
+        &self,
+        bundles: impl IntoIterator<Item = &'b FluentBundle<FluentResource>>,
+        key: &str,
+        attribute: Option<&str>,
+        args: Option<&FluentArgs>,
+    ) -> Option<String> {
+        self.format_with_first_at(bundles, key, attribute, args)
+            .map(|(_, text, _)| text)
+    }
+
+    /// Like format_with_first(), also returning the position of the bundle
+    /// that was used, and any errors.
+    fn format_with_first_at<'b>(
+        &self,
+        bundles: impl IntoIterator<Item = &'b FluentBundle<FluentResource>>,
+        key: &str,
+        attribute: Option<&str>,
+        args: Option<&FluentArgs>,
+    ) -> Option<(usize, String, Vec<FluentError>)> {
+        for (idx, bundle) in bundles.into_iter().enumerate() {
             let msg = match bundle.get_message(key) {
                 Some(msg) => msg,
                 // not translated in this bundle
                 None => continue,
             };
 
-            let pat = match msg.value {
+            let pat = match attribute
+                .and_then(|attr| msg.attributes.get(attr).copied())
+                .or(msg.value)
+            {
                 Some(val) => val,
                 // empty value
                 None => continue,
             };
 
             let mut errs = vec![];
-            let out = bundle.format_pattern(pat, args.as_ref(), &mut errs);
+            let out = bundle.format_pattern(pat, args, &mut errs);
             if !errs.is_empty() {
                 error!(self.log, "Error(s) in translation '{}': {:?}", key, errs);
             }
             // clone so we can discard args
-            return out.to_string().into();
+            return Some((idx, out.to_string(), errs));
         }
 
-        // return the key name if it was missing
-        key.to_string().into()
+        None
     }
 
-    /// Return text from configured locales for use with the JS Fluent implementation.
-    pub fn resources_for_js(&self) -> ResourcesForJavascript {
+    /// Get translation from the user's languages only, returning None
+    /// instead of the English text if no configured language has it.
+    pub fn tr_no_fallback(&self, key: TR) -> Option<String> {
+        let key = FLUENT_KEYS[key as usize];
+        self.tr_no_fallback_(key)
+    }
+
+    fn tr_no_fallback_(&self, key: &str) -> Option<String> {
         let inner = self.inner.lock().unwrap();
-        ResourcesForJavascript {
-            langs: inner.langs.iter().map(ToString::to_string).collect(),
-            resources: inner.resource_text.clone(),
-        }
+        let (_template, localized) = inner.bundles.split_last().unwrap();
+        self.format_with_first(localized, key, None, None)
     }
-}
 
-struct I18nInner {
-    // bundles in preferred language order, with template English as the
-    // last element
-    bundles: Vec<FluentBundle<FluentResource>>,
-    langs: Vec<LanguageIdentifier>,
-    resource_text: Vec<&'static str>,
-}
+    /// Get translation with one or more arguments, trying the provided
+    /// language after the configured ones but before English. The
+    /// language's bundle is built on each call, so this is intended for
+    /// the occasional string that needs a different fallback.
+    pub fn trn_fallback(&self, key: TR, args: FluentArgs, fallback: &LanguageIdentifier) -> String {
+        let key = FLUENT_KEYS[key as usize];
+        self.trn_fallback_(key, args, fallback)
+    }
 
-fn set_bundle_formatter_for_langs<T>(bundle: &mut FluentBundle<T>, langs: &[LanguageIdentifier]) {
-    let num_formatter = NumberFormatter::new(langs);
-    let formatter = move |val: &FluentValue, _intls: &Mutex<IntlLangMemoizer>| -> Option<String> {
-        match val {
-            FluentValue::Number(n) => {
-                let mut num = n.clone();
-                num.options.maximum_fraction_digits = Some(2);
-                Some(num_formatter.format(num.as_string().to_string()))
-            }
-            _ => None,
-        }
-    };
+    fn trn_fallback_(&self, key: &str, args: FluentArgs, fallback: &LanguageIdentifier) -> String {
+        let inner = self.inner.lock().unwrap();
+        let fallback_bundle = ftl_localized_text(fallback).and_then(|text| {
+            get_bundle_with_extra(
+                text,
+                Some(fallback),
+                &inner.ftl_folder,
+                &inner.langs,
+                &inner.group_numbers,
+                &mut HashSet::new(),
+                &mut HashSet::new(),
+                &self.log,
+            )
+        });
+        let (template, localized) = inner.bundles.split_last().unwrap();
 
-    bundle.set_formatter(Some(formatter));
-}
+        self.format_with_first(
+            localized
+                .iter()
+                .chain(fallback_bundle.as_ref())
+                .chain(Some(template)),
+            key,
+            None,
+            Some(&args),
+        )
+        // return the key name if it was missing
+        .unwrap_or_else(|| key.to_string())
+    }
 
-fn first_available_num_format_locale(langs: &[LanguageIdentifier]) -> Option<Locale> {
-    for lang in langs {
-        if let Some(locale) = num_format_locale(lang) {
-            return Some(locale);
-        }
+    /// Get translation with zero arguments for the given context, falling
+    /// back on the message's value if it has no context-specific text.
+    pub fn tr_context(&self, key: TR, context: TrContext) -> Cow<str> {
+        let key = FLUENT_KEYS[key as usize];
+        self.tr_context_(key, context)
     }
-    None
-}
 
-// try to locate a num_format locale for a given language identifier
-fn num_format_locale(lang: &LanguageIdentifier) -> Option<Locale> {
-    // region provided?
-    if let Some(region) = lang.region() {
-        let code = format!("{}_{}", lang.language(), region);
-        if let Ok(locale) = Locale::from_name(code) {
-            return Some(locale);
-        }
+    fn tr_context_<'a>(&'a self, key: &str, context: TrContext) -> Cow<'a, str> {
+        let inner = self.inner.lock().unwrap();
+        self.format_with_first(&inner.bundles, key, context.attribute(), None)
+            // return the key name if it was missing
+            .unwrap_or_else(|| key.to_string())
+            .into()
     }
-    // try the language alone
-    Locale::from_name(lang.language()).ok()
-}
 
-struct NumberFormatter {
-    decimal_separator: &'static str,
-}
+    /// Translate a count and noun, such as "1 card" or "5 cards". The
+    /// message should have a plural selector on $count.
+    pub fn count_noun(&self, key: TR, count: i64) -> String {
+        self.count_noun_with_var(key, "count", count)
+    }
 
-impl NumberFormatter {
-    fn new(langs: &[LanguageIdentifier]) -> Self {
-        if let Some(locale) = first_available_num_format_locale(langs) {
-            Self {
-                decimal_separator: locale.decimal(),
-            }
-        } else {
-            // fallback on English defaults
+    /// Like count_noun(), for messages whose selector uses a variable
+    /// other than $count, such as $hats.
+    pub fn count_noun_with_var(&self, key: TR, var: &str, count: i64) -> String {
+        let key = FLUENT_KEYS[key as usize];
+        self.count_noun_(key, var, count)
+    }
+
+    fn count_noun_(&self, key: &str, var: &str, count: i64) -> String {
+        self.tr_(key, Some(tr_args![var => count])).into()
+    }
+
+    /// Format a message with a single number, also returning the plural
+    /// category the number falls into, for checking a message's selectors.
+    pub fn trn_with_category(&self, key: TR, var: &str, n: f64) -> (String, PluralCategory) {
+        let key = FLUENT_KEYS[key as usize];
+        self.trn_with_category_(key, var, n)
+    }
+
+    fn trn_with_category_(&self, key: &str, var: &str, n: f64) -> (String, PluralCategory) {
+        let text = self.tr_(key, Some(tr_args![var => n])).into();
+        (text, self.plural_category(n))
+    }
+
+    /// The plural category of n in the preferred language. As all bundles
+    /// share the same locale list, this is also the category fluent uses
+    /// when the English template is the fallback.
+    fn plural_category(&self, n: f64) -> PluralCategory {
+        self.plural_rules()
+            .and_then(|rules| rules.select(n).ok())
+            .unwrap_or(PluralCategory::OTHER)
+    }
+
+    fn plural_rules(&self) -> Option<PluralRules> {
+        let lang = self.inner.lock().unwrap().langs[0].clone();
+        // like fluent, fall back on the bare language, then English
+        let candidates = [
+            Some(lang.clone()),
+            lang.language().parse().ok(),
+            Some(langid!("en")),
+        ];
+        candidates
+            .iter()
+            .flatten()
+            .filter_map(|lang| PluralRules::create(lang.clone(), PluralRuleType::CARDINAL).ok())
+            .next()
+    }
+
+    /// Format a message once for each plural category of the preferred
+    /// language, using an example number from that category, so all of
+    /// its variants can be previewed.
+    pub fn preview_variants(&self, key: TR, var: &str) -> Vec<(PluralCategory, String)> {
+        let key = FLUENT_KEYS[key as usize];
+        self.preview_variants_(key, var)
+    }
+
+    fn preview_variants_(&self, key: &str, var: &str) -> Vec<(PluralCategory, String)> {
+        let rules = match self.plural_rules() {
+            Some(rules) => rules,
+            None => return vec![],
+        };
+
+        // find the first example of each category; some languages have
+        // categories only reached by fractions
+        let mut examples: Vec<(PluralCategory, f64)> = vec![];
+        for n in (0..=200).map(f64::from).chain(vec![0.5, 1.5]) {
+            if let Ok(category) = rules.select(n) {
+                if !examples.iter().any(|(c, _)| *c == category) {
+                    examples.push((category, n));
+                }
+            }
+        }
+        examples.sort_by_key(|(category, _)| *category as u8);
+
+        examples
+            .into_iter()
+            .map(|(category, n)| (category, self.tr_(key, Some(tr_args![var => n])).into()))
+            .collect()
+    }
+
+    /// Get translation, only calling build_args if the message references
+    /// any variables, for when args are expensive to create.
+    pub fn trn_lazy<'a>(&self, key: TR, build_args: impl FnOnce() -> FluentArgs<'a>) -> String {
+        let key = FLUENT_KEYS[key as usize];
+        self.trn_lazy_(key, build_args)
+    }
+
+    fn trn_lazy_<'a>(&self, key: &str, build_args: impl FnOnce() -> FluentArgs<'a>) -> String {
+        let needs_args = {
+            let inner = self.inner.lock().unwrap();
+            inner
+                .bundles
+                .iter()
+                .filter_map(|bundle| bundle.get_message(key).map(|msg| (bundle, msg)))
+                .find(|(_, msg)| msg.value.is_some())
+                .map(|(bundle, msg)| !message_variables(bundle, &msg).is_empty())
+                .unwrap_or(false)
+        };
+        let args = if needs_args { Some(build_args()) } else { None };
+        self.tr_(key, args).into()
+    }
+
+    /// Get translation for a message containing one or more counts, eg
+    /// "{ $cards -> ... } and { $notes -> ... }". Each count is passed as
+    /// a number, so the message's plural selectors pick the right variants.
+    pub fn trn_counts(&self, key: TR, counts: &[(&str, i64)]) -> String {
+        let key = FLUENT_KEYS[key as usize];
+        self.trn_counts_(key, counts)
+    }
+
+    fn trn_counts_(&self, key: &str, counts: &[(&str, i64)]) -> String {
+        let mut args = FluentArgs::new();
+        for (name, count) in counts {
+            args.insert(*name, (*count).into());
+        }
+        self.tr_(key, Some(args)).into()
+    }
+
+    /// Get translation with one or more arguments, HTML-escaping any string
+    /// args first, so text such as a user's deck name can't break the markup
+    /// of a message shown in a webview. Markup in the message itself is kept.
+    pub fn trn_html(&self, key: TR, args: FluentArgs) -> String {
+        let key = FLUENT_KEYS[key as usize];
+        self.trn_html_(key, args)
+    }
+
+    fn trn_html_(&self, key: &str, args: FluentArgs) -> String {
+        let args = args
+            .into_iter()
+            .map(|(name, val)| match val {
+                FluentValue::String(text) => (
+                    name,
+                    FluentValue::String(htmlescape::encode_minimal(&text).into()),
+                ),
+                other => (name, other),
+            })
+            .collect();
+        self.tr_(key, Some(args)).into()
+    }
+
+    /// Get translation with one or more arguments, returning an error
+    /// instead of partial output if no configured language could
+    /// format the message cleanly.
+    pub fn trn_strict(&self, key: TR, args: FluentArgs) -> Result<String> {
+        let key = FLUENT_KEYS[key as usize];
+        self.trn_strict_(key, args)
+    }
+
+    fn trn_strict_(&self, key: &str, args: FluentArgs) -> Result<String> {
+        let mut last_errs = vec![];
+        for bundle in &self.inner.lock().unwrap().bundles {
+            let pat = match bundle.get_message(key).and_then(|msg| msg.value) {
+                Some(pat) => pat,
+                // missing or empty in this bundle
+                None => continue,
+            };
+
+            let mut errs = vec![];
+            let out = bundle.format_pattern(pat, Some(&args), &mut errs);
+            if errs.is_empty() {
+                return Ok(out.to_string());
+            }
+            // try the next bundle
+            last_errs = errs;
+        }
+
+        Err(AnkiError::invalid_input(if last_errs.is_empty() {
+            format!("missing translation '{}'", key)
+        } else {
+            format!("error(s) in translation '{}': {:?}", key, last_errs)
+        }))
+    }
+
+    /// A fingerprint of the source of the message that would be used to
+    /// translate the given key. It changes when the message is modified,
+    /// so callers can tell if a cached copy is stale.
+    pub fn key_fingerprint(&self, key: TR) -> Option<u64> {
+        let key = FLUENT_KEYS[key as usize];
+        self.key_fingerprint_(key)
+    }
+
+    fn key_fingerprint_(&self, key: &str) -> Option<u64> {
+        let inner = self.inner.lock().unwrap();
+        let msg = inner
+            .bundles
+            .iter()
+            .filter_map(|bundle| bundle.get_message(key))
+            .find(|msg| msg.value.is_some())?;
+
+        let mut hasher = Hasher::new();
+        hasher.update(message_source(&msg).as_bytes());
+        let mut buf = [0; 8];
+        buf.copy_from_slice(&hasher.finalize().as_bytes()[0..8]);
+        Some(u64::from_le_bytes(buf))
+    }
+
+    /// The languages consulted when translating key, in order, ending
+    /// with the one that provides the translation. None represents the
+    /// English template. If no language has the key, all are returned.
+    pub fn resolution_chain(&self, key: TR) -> Vec<Option<LanguageIdentifier>> {
+        let key = FLUENT_KEYS[key as usize];
+        self.resolution_chain_(key)
+    }
+
+    fn resolution_chain_(&self, key: &str) -> Vec<Option<LanguageIdentifier>> {
+        let inner = self.inner.lock().unwrap();
+        let mut chain = vec![];
+        for (bundle, lang) in inner.bundles.iter().zip(&inner.bundle_langs) {
+            chain.push(lang.clone());
+            if bundle.get_message(key).and_then(|msg| msg.value).is_some() {
+                break;
+            }
+        }
+        chain
+    }
+
+    /// The keys of messages that reference $var in any configured language,
+    /// in sorted order.
+    pub fn keys_using_var(&self, var: &str) -> Vec<String> {
+        let inner = self.inner.lock().unwrap();
+        let mut keys: Vec<_> = inner
+            .keys
+            .iter()
+            .filter(|key| {
+                inner.bundles.iter().any(|bundle| {
+                    bundle
+                        .get_message(key)
+                        .map(|msg| message_variables(bundle, &msg).contains(var))
+                        .unwrap_or(false)
+                })
+            })
+            .cloned()
+            .collect();
+        keys.sort();
+        keys
+    }
+
+    /// Selector variant keys in translations that are neither used by the
+    /// English template nor a plural category, which usually means a
+    /// translator has translated a keyword that must be left as-is.
+    /// Returns sorted (message key, variant key) pairs.
+    pub fn invalid_variant_keys(&self) -> Vec<(String, String)> {
+        let inner = self.inner.lock().unwrap();
+        let (template, localized) = inner.bundles.split_last().unwrap();
+        let plural_categories = ["zero", "one", "two", "few", "many", "other"];
+
+        let mut invalid = vec![];
+        for key in &inner.keys {
+            let english = template
+                .get_message(key)
+                .map(|msg| message_variant_keys(&msg))
+                .unwrap_or_default();
+            for bundle in localized {
+                if let Some(msg) = bundle.get_message(key) {
+                    for name in message_variant_keys(&msg) {
+                        if !english.contains(name) && !plural_categories.contains(&name) {
+                            invalid.push((key.clone(), name.to_string()));
+                        }
+                    }
+                }
+            }
+        }
+        invalid.sort();
+        invalid.dedup();
+        invalid
+    }
+
+    /// Term references that can't be resolved, as sorted (message key,
+    /// term name) pairs. Fluent only looks a term up in the bundle of the
+    /// message referencing it, so a translation that uses a term must also
+    /// define it, even if the English template does.
+    pub fn unresolved_term_references(&self) -> Vec<(String, String)> {
+        let inner = self.inner.lock().unwrap();
+
+        let mut unresolved = vec![];
+        for key in &inner.keys {
+            for (bundle, terms) in inner.bundles.iter().zip(&inner.bundle_terms) {
+                if let Some(msg) = bundle.get_message(key) {
+                    for name in message_term_references(&msg) {
+                        if !terms.contains(name) {
+                            unresolved.push((key.clone(), name.to_string()));
+                        }
+                    }
+                }
+            }
+        }
+        unresolved.sort();
+        unresolved.dedup();
+        unresolved
+    }
+
+    /// Keys whose English template message has no value or an empty one.
+    /// These are almost always mistakes, as the key name is shown instead
+    /// of a fallback when a translation is missing. Returns sorted keys.
+    pub fn empty_fallback_keys(&self) -> Vec<String> {
+        let inner = self.inner.lock().unwrap();
+        let template = inner.bundles.last().unwrap();
+        let mut keys: Vec<_> = inner
+            .keys
+            .iter()
+            .filter(|key| {
+                template
+                    .get_message(key)
+                    .map(|msg| msg.value.map(pattern_is_empty).unwrap_or(true))
+                    .unwrap_or(false)
+            })
+            .cloned()
+            .collect();
+        keys.sort();
+        keys
+    }
+
+    /// (key, English source, translated source) for each message that
+    /// has a translation in lang, sorted by key, for seeding another
+    /// language's translations in a CAT tool. The sources are in the
+    /// canonical form of message_source(), so selectors are kept. Like
+    /// trn_fallback(), lang's bundle is built on each call, so lang need
+    /// not be one of the configured languages.
+    pub fn export_translation_memory(
+        &self,
+        lang: &LanguageIdentifier,
+    ) -> Vec<(String, String, String)> {
+        let inner = self.inner.lock().unwrap();
+        let bundle = match ftl_localized_text(lang).and_then(|text| {
+            get_bundle_with_extra(
+                text,
+                Some(lang),
+                &inner.ftl_folder,
+                &inner.langs,
+                &inner.group_numbers,
+                &mut HashSet::new(),
+                &mut HashSet::new(),
+                &self.log,
+            )
+        }) {
+            Some(bundle) => bundle,
+            None => return vec![],
+        };
+        let template = inner.bundles.last().unwrap();
+
+        let mut pairs: Vec<_> = inner
+            .keys
+            .iter()
+            .filter_map(|key| {
+                let english = template.get_message(key)?;
+                let translated = bundle.get_message(key)?;
+                Some((
+                    key.clone(),
+                    message_source(&english),
+                    message_source(&translated),
+                ))
+            })
+            .collect();
+        pairs.sort();
+        pairs
+    }
+
+    /// Read ftl text from the provided reader, and add it to the preferred
+    /// language's bundle, overriding any existing messages with the same
+    /// keys. Like files in the ftl folder, text that can't be read or
+    /// parsed is logged and ignored. The added text is also included in
+    /// resources_for_js().
+    pub fn add_resource_from_reader(&self, reader: &mut impl Read) {
+        let mut text = String::new();
+        if let Err(e) = reader.read_to_string(&mut text) {
+            error!(self.log, "Error reading FTL text: {:?}", e);
+            return;
+        }
+
+        match FluentResource::try_new(text.clone()) {
+            Ok(res) => self.add_resource_overriding(0, text, res),
+            Err((_res, e)) => error!(self.log, "Unable to parse translations file: {:?}", e),
+        }
+    }
+
+    /// Parse the provided ftl text and add it to the bundle of lang, or
+    /// the English template if None, overriding any existing messages
+    /// with the same keys. Unlike add_resource_from_reader(), the patch is
+    /// rejected as a whole if any of it fails to parse, or if lang is not
+    /// one of the loaded translations. Applied patches are included in
+    /// resources_for_js(), so strings rendered in the webview see them too.
+    pub fn apply_patch(&self, lang: Option<&LanguageIdentifier>, patch_ftl: &str) -> Result<()> {
+        let res = parse_patch(patch_ftl)?;
+        let idx = self.bundle_index(lang)?;
+        self.add_resource_overriding(idx, patch_ftl.into(), res);
+        Ok(())
+    }
+
+    /// The effect apply_patch() would have, as (key, current text, patched
+    /// text) for each message in the patch, without changing anything.
+    pub fn preview_patch(
+        &self,
+        lang: Option<&LanguageIdentifier>,
+        patch_ftl: &str,
+    ) -> Result<Vec<(String, String, String)>> {
+        let res = parse_patch(patch_ftl)?;
+        let keys: Vec<_> = message_ids(&res).collect();
+        let idx = self.bundle_index(lang)?;
+
+        let patched: Vec<_> = {
+            let inner = self.inner.lock().unwrap();
+            // rebuild the patched bundle, so that references in the patch
+            // resolve as they would after apply_patch()
+            let text = lang
+                .and_then(ftl_localized_text)
+                .unwrap_or_else(ftl_template_text);
+            let mut bundle = get_bundle_with_extra(
+                text,
+                lang,
+                &inner.ftl_folder,
+                &inner.langs,
+                &inner.group_numbers,
+                &mut HashSet::new(),
+                &mut HashSet::new(),
+                &self.log,
+            )
+            .ok_or_else(|| AnkiError::invalid_input("unable to rebuild translations"))?;
+            for text in &inner.runtime_text[idx] {
+                if let Ok(res) = FluentResource::try_new(text.clone()) {
+                    bundle.add_resource_overriding(res);
+                }
+            }
+            bundle.add_resource_overriding(res);
+            let bundles: Vec<_> = inner
+                .bundles
+                .iter()
+                .enumerate()
+                .map(|(i, existing)| if i == idx { &bundle } else { existing })
+                .collect();
+
+            keys.into_iter()
+                .map(|key| {
+                    let text = self
+                        .format_with_first(bundles.iter().copied(), &key, None, None)
+                        .unwrap_or_default();
+                    (key, text)
+                })
+                .collect()
+        };
+
+        Ok(patched
+            .into_iter()
+            .map(|(key, patched)| {
+                let current = self.tr_(&key, None).into_owned();
+                (key, current, patched)
+            })
+            .collect())
+    }
+
+    /// The position of lang's bundle, or the template's if None.
+    fn bundle_index(&self, lang: Option<&LanguageIdentifier>) -> Result<usize> {
+        self.inner
+            .lock()
+            .unwrap()
+            .bundle_langs
+            .iter()
+            .position(|bundle_lang| bundle_lang.as_ref() == lang)
+            .ok_or_else(|| {
+                AnkiError::invalid_input(format!(
+                    "no translations loaded for {}",
+                    lang.map(ToString::to_string).unwrap_or_default()
+                ))
+            })
+    }
+
+    /// Add text to the bundle at idx, overriding existing messages.
+    fn add_resource_overriding(&self, idx: usize, text: String, res: FluentResource) {
+        let mut inner = self.inner.lock().unwrap();
+        // overridden messages are still held by the bundle
+        let replaced: usize = message_ids(&res)
+            .filter_map(|key| message_size(&inner.bundles[idx], &key))
+            .sum();
+        inner.replaced_bytes += replaced;
+        inner.keys.extend(message_ids(&res));
+        inner.bundle_terms[idx].extend(term_ids(&res));
+        inner.bundles[idx].add_resource_overriding(res);
+        inner.runtime_text[idx].push(text);
+    }
+
+    /// Wrap text, such as a deck name, in the preferred language's
+    /// quotation marks.
+    pub fn quote(&self, text: &str) -> String {
+        self.quote_with_level(text, 0)
+    }
+
+    /// Like quote(), using the marks for a quote inside another quote.
+    pub fn quote_nested(&self, text: &str) -> String {
+        self.quote_with_level(text, 1)
+    }
+
+    fn quote_with_level(&self, text: &str, level: usize) -> String {
+        let lang = self.inner.lock().unwrap().langs[0].clone();
+        let (open, close) = quotation_marks(&lang)[level];
+        format!("{}{}{}", open, text, close)
+    }
+
+    /// Whether numbers are shown with grouping separators, such as
+    /// "1,234,567" in English. Off by default, so existing output is
+    /// unchanged unless a caller opts in.
+    pub fn set_number_grouping(&self, enabled: bool) {
+        self.inner
+            .lock()
+            .unwrap()
+            .group_numbers
+            .store(enabled, Ordering::Relaxed);
+    }
+
+    /// Undo any changes made since new(): patches and other added text
+    /// are dropped by rebuilding the bundles, and number grouping and
+    /// tracing are turned off. The preferred languages are unchanged.
+    pub fn reset(&self) {
+        let (codes, ftl_folder) = {
+            let inner = self.inner.lock().unwrap();
+            // the fallback English is added again by new()
+            let (_fallback, preferred) = inner.langs.split_last().unwrap();
+            let codes: Vec<_> = preferred.iter().map(ToString::to_string).collect();
+            (codes, inner.ftl_folder.clone())
+        };
+        let fresh = Self::new(&codes, ftl_folder, self.log.clone());
+        let mut fresh_inner = fresh.inner.lock().unwrap();
+        let mut inner = self.inner.lock().unwrap();
+        fresh_inner.rejected_codes = std::mem::take(&mut inner.rejected_codes);
+        fresh_inner.extensions = std::mem::take(&mut inner.extensions);
+        std::mem::swap(&mut *inner, &mut *fresh_inner);
+    }
+
+    /// Parse a number the user entered in the preferred language's
+    /// format, eg "1.234,56" in German.
+    pub fn parse_number(&self, text: &str) -> Result<f64> {
+        let inner = self.inner.lock().unwrap();
+        NumberFormatter::new(&inner.langs)
+            .parse(text)
+            .ok_or_else(|| AnkiError::invalid_input(format!("invalid number: {}", text)))
+    }
+
+    /// A rough count of the bytes of message text held by the bundles,
+    /// which grows as resources are added with apply_patch() and
+    /// add_resource_from_reader().
+    pub fn memory_estimate(&self) -> usize {
+        let inner = self.inner.lock().unwrap();
+        let keys = &inner.keys;
+        let loaded: usize = inner
+            .bundles
+            .iter()
+            .flat_map(|bundle| keys.iter().filter_map(move |key| message_size(bundle, key)))
+            .sum();
+        loaded + inner.replaced_bytes
+    }
+
+    /// The configured languages as BCP-47 tags, including any extensions
+    /// from the codes passed to new(), eg ja-JP-u-ca-japanese. Extensions
+    /// don't affect which translation is used, but are kept for date and
+    /// calendar formatting.
+    pub fn locale_tags(&self) -> Vec<String> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .langs
+            .iter()
+            .zip(&inner.extensions)
+            .map(|(lang, ext)| match ext {
+                Some(ext) => format!("{}-{}", lang, ext),
+                None => lang.to_string(),
+            })
+            .collect()
+    }
+
+    /// Any locale codes passed to new() that could not be understood, so
+    /// the user can be warned about them.
+    pub fn rejected_codes(&self) -> Vec<String> {
+        self.inner.lock().unwrap().rejected_codes.clone()
+    }
+
+    /// The text direction of the preferred language.
+    pub fn text_direction(&self) -> CharacterDirection {
+        self.inner.lock().unwrap().langs[0].character_direction()
+    }
+
+    /// The folder translations are read from, as passed to new().
+    pub fn locale_folder(&self) -> PathBuf {
+        self.inner.lock().unwrap().ftl_folder.clone()
+    }
+
+    /// Return an error if the ftl folder is missing or empty. I18n::new()
+    /// silently falls back on the embedded text in that case, so builds
+    /// that depend on translations on disk should call this after
+    /// construction.
+    pub fn check_folder(&self) -> Result<()> {
+        let inner = self.inner.lock().unwrap();
+        if fs::read_dir(&inner.ftl_folder)?.next().is_none() {
+            return Err(AnkiError::IOError {
+                info: format!("ftl folder is empty: {:?}", inner.ftl_folder),
+            });
+        }
+        Ok(())
+    }
+
+    /// Return text from configured locales for use with the JS Fluent implementation.
+    pub fn resources_for_js(&self) -> ResourcesForJavascript {
+        let inner = self.inner.lock().unwrap();
+        let mut resources: Vec<Cow<str>> = inner
+            .resource_text
+            .iter()
+            .map(|text| (*text).into())
+            .collect();
+        for (resource, runtime_text) in resources.iter_mut().zip(&inner.runtime_text) {
+            if !runtime_text.is_empty() {
+                // the JS bundles keep the first definition of a message, so
+                // the newest text goes first
+                let mut text: Vec<_> = runtime_text.iter().rev().cloned().collect();
+                text.push(resource.to_string());
+                *resource = text.join("\n").into();
+            }
+        }
+        ResourcesForJavascript {
+            langs: inner.langs.iter().map(ToString::to_string).collect(),
+            resources,
+        }
+    }
+}
+
+struct I18nInner {
+    // bundles in preferred language order, with template English as the
+    // last element
+    bundles: Vec<FluentBundle<FluentResource>>,
+    // the language of each bundle, with None for the template
+    bundle_langs: Vec<Option<LanguageIdentifier>>,
+    // ids of the messages defined in any bundle
+    keys: HashSet<String>,
+    // ids of the terms defined in each bundle
+    bundle_terms: Vec<HashSet<String>>,
+    langs: Vec<LanguageIdentifier>,
+    resource_text: Vec<&'static str>,
+    ftl_folder: PathBuf,
+    // locale codes passed to new() that could not be parsed
+    rejected_codes: Vec<String>,
+    // any extension subtags of each of langs, which LanguageIdentifier
+    // can't hold
+    extensions: Vec<Option<String>>,
+    // read by the bundles' number formatters; see set_number_grouping()
+    group_numbers: Arc<AtomicBool>,
+    // size of messages that resources added at runtime have overridden
+    replaced_bytes: usize,
+    trace: Option<Arc<dyn Fn(TraceEvent) + Send + Sync>>,
+    // text added to each bundle after construction, oldest first
+    runtime_text: Vec<Vec<String>>,
+}
+
+/// Numbers are shown with at most two decimal places by default. A caller
+/// can format a single arg differently by passing a FluentValue::Number
+/// with its own fraction digit options, which are left as-is. Digits are
+/// only grouped while group_numbers is set.
+fn set_bundle_formatter_for_langs<T>(
+    bundle: &mut FluentBundle<T>,
+    langs: &[LanguageIdentifier],
+    group_numbers: Arc<AtomicBool>,
+) {
+    let num_formatter = NumberFormatter::new(langs);
+    let formatter = move |val: &FluentValue, _intls: &Mutex<IntlLangMemoizer>| -> Option<String> {
+        match val {
+            FluentValue::Number(n) => {
+                let mut num = n.clone();
+                if num.options.maximum_fraction_digits.is_none() {
+                    num.options.maximum_fraction_digits = Some(2);
+                }
+                let text = num_formatter.format(num.as_string().to_string());
+                if group_numbers.load(Ordering::Relaxed) {
+                    Some(num_formatter.group(&text))
+                } else {
+                    Some(text)
+                }
+            }
+            _ => None,
+        }
+    };
+
+    bundle.set_formatter(Some(formatter));
+}
+
+fn first_available_num_format_locale(langs: &[LanguageIdentifier]) -> Option<Locale> {
+    for lang in langs {
+        if let Some(locale) = num_format_locale(lang) {
+            return Some(locale);
+        }
+    }
+    None
+}
+
+// try to locate a num_format locale for a given language identifier
+fn num_format_locale(lang: &LanguageIdentifier) -> Option<Locale> {
+    // region provided?
+    if let Some(region) = lang.region() {
+        let code = format!("{}_{}", lang.language(), region);
+        if let Ok(locale) = Locale::from_name(code) {
+            return Some(locale);
+        }
+    }
+    // try the language alone
+    Locale::from_name(lang.language()).ok()
+}
+
+struct NumberFormatter {
+    decimal_separator: &'static str,
+    group_separator: &'static str,
+    minus_sign: &'static str,
+}
+
+impl NumberFormatter {
+    fn new(langs: &[LanguageIdentifier]) -> Self {
+        if let Some(locale) = first_available_num_format_locale(langs) {
+            Self {
+                decimal_separator: locale.decimal(),
+                group_separator: locale.separator(),
+                minus_sign: locale.minus_sign(),
+            }
+        } else {
+            // fallback on English defaults
             Self {
                 decimal_separator: ".",
+                group_separator: ",",
+                minus_sign: "-",
             }
         }
     }
@@ -405,26 +1835,177 @@ impl NumberFormatter {
             num
         }
     }
+
+    /// Given a number formatted by format(), separate its integer digits
+    /// into groups of three, eg "1234567,5" becomes "1.234.567,5" in German.
+    fn group(&self, num: &str) -> String {
+        let (int_part, fraction) = match num.find(self.decimal_separator) {
+            Some(idx) => num.split_at(idx),
+            None => (num, ""),
+        };
+        let (sign, digits) = if int_part.starts_with('-') {
+            int_part.split_at(1)
+        } else {
+            ("", int_part)
+        };
+
+        let mut out = sign.to_string();
+        for (idx, digit) in digits.chars().enumerate() {
+            if idx > 0 && (digits.len() - idx) % 3 == 0 {
+                out.push_str(self.group_separator);
+            }
+            out.push(digit);
+        }
+        out.push_str(fraction);
+        out
+    }
+
+    /// Parse a number written with the locale's separators, eg "1.234,56"
+    /// in German. Grouping separators and whitespace are ignored. Apart
+    /// from a leading sign, only digits and separators are accepted, so
+    /// input like "NaN" or "1e5" is rejected.
+    fn parse(&self, text: &str) -> Option<f64> {
+        let text: String = text.chars().filter(|c| !c.is_whitespace()).collect();
+
+        let mut negative = false;
+        let mut body = text.as_str();
+        for (sign, is_negative) in &[
+            (self.minus_sign, true),
+            ("-", true),
+            ("\u{2212}", true),
+            ("+", false),
+        ] {
+            if let Some(rest) = body.strip_prefix(sign) {
+                negative = *is_negative;
+                body = rest;
+                break;
+            }
+        }
+
+        let mut body = body.to_string();
+        if !self.group_separator.is_empty() {
+            body = body.replace(self.group_separator, "");
+        }
+        let body = body.replace(self.decimal_separator, ".");
+
+        let valid = body.chars().any(|c| c.is_ascii_digit())
+            && body.chars().all(|c| c.is_ascii_digit() || c == '.')
+            && body.matches('.').count() <= 1;
+        if !valid {
+            return None;
+        }
+
+        let num: f64 = body.parse().ok()?;
+        if !num.is_finite() {
+            return None;
+        }
+        Some(if negative { -num } else { num })
+    }
 }
 
 #[derive(Serialize)]
 pub struct ResourcesForJavascript {
     langs: Vec<String>,
-    resources: Vec<&'static str>,
+    resources: Vec<Cow<'static, str>>,
 }
 
 #[cfg(test)]
 mod test {
-    use crate::i18n::NumberFormatter;
-    use crate::i18n::{tr_args, I18n};
+    use crate::i18n::{
+        args_with_replacement_chars, ftl_localized_text, message_source, message_variables,
+        unused_args, NumberFormatter,
+    };
+    use crate::i18n::{tr_args, I18n, TrContext, TraceEvent, TR};
     use crate::log;
+    use fluent::types::{FluentNumber, FluentNumberOptions};
+    use fluent::{FluentArgs, FluentValue};
+    use intl_pluralrules::PluralCategory;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use slog::{o, Drain, Logger, Never, OwnedKVList, Record};
+    use std::io::Cursor;
     use std::path::PathBuf;
-    use unic_langid::langid;
+    use std::sync::{Arc, Mutex};
+    use unic_langid::{langid, CharacterDirection};
+
+    fn test_ftl_dir() -> PathBuf {
+        let mut ftl_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        ftl_dir.push("tests/support/ftl");
+        ftl_dir
+    }
+
+    /// An I18n using the test fixtures, with the provided languages.
+    fn test_i18n(langs: &[&str]) -> I18n {
+        I18n::new(langs, test_ftl_dir(), log::terminal())
+    }
+
+    /// Like test_i18n(), also returning the messages it logs.
+    fn test_i18n_with_log(langs: &[&str]) -> (I18n, Arc<Mutex<Vec<String>>>) {
+        let logged = Arc::new(Mutex::new(vec![]));
+        let log = Logger::root(CaptureDrain(logged.clone()).fuse(), o!());
+        (I18n::new(langs, test_ftl_dir(), log), logged)
+    }
+
+    struct CaptureDrain(Arc<Mutex<Vec<String>>>);
+
+    impl Drain for CaptureDrain {
+        type Ok = ();
+        type Err = Never;
+
+        fn log(&self, record: &Record, _values: &OwnedKVList) -> Result<(), Never> {
+            self.0.lock().unwrap().push(record.msg().to_string());
+            Ok(())
+        }
+    }
 
     #[test]
     fn numbers() {
         let fmter = NumberFormatter::new(&[langid!("pl-PL")]);
         assert_eq!(&fmter.format("1.007".to_string()), "1,007");
+
+        let fmter = NumberFormatter::new(&[langid!("de")]);
+        assert_eq!(fmter.group("-1234567,25"), "-1.234.567,25");
+        assert_eq!(fmter.group("123"), "123");
+    }
+
+    #[test]
+    fn grouping() {
+        let i18n = test_i18n(&["zz"]);
+        let args = || tr_args!["one"=>1234567, "two"=>-1234.5];
+        assert_eq!(
+            i18n.tr_("two-args-key", Some(args())),
+            "two args: 1234567 and -1234.5"
+        );
+
+        i18n.set_number_grouping(true);
+        assert_eq!(
+            i18n.tr_("two-args-key", Some(args())),
+            "two args: 1,234,567 and -1,234.5"
+        );
+    }
+
+    #[test]
+    fn parse_numbers() {
+        let log = log::terminal();
+        let i18n = I18n::new(&["de"], "", log.clone());
+        assert_eq!(i18n.parse_number("1.234,56"), Ok(1234.56));
+        assert_eq!(i18n.parse_number("-3,5"), Ok(-3.5));
+        assert!(i18n.parse_number("abc").is_err());
+
+        let i18n = I18n::new(&["en"], "", log.clone());
+        assert_eq!(i18n.parse_number("1,234.56"), Ok(1234.56));
+        assert_eq!(i18n.parse_number(" 42 "), Ok(42.0));
+        assert_eq!(i18n.parse_number("+5"), Ok(5.0));
+        for text in &[
+            "NaN", "inf", "infinity", "1e400", "1e5", "0x10", "--1", "1-", "1.2.3", ".", "-", "",
+        ] {
+            assert!(i18n.parse_number(text).is_err(), "{}", text);
+        }
+
+        // Swedish and Finnish write negative numbers with U+2212
+        let i18n = I18n::new(&["sv"], "", log.clone());
+        assert_eq!(i18n.parse_number("\u{2212}1\u{a0}234,5"), Ok(-1234.5));
+        let i18n = I18n::new(&["fi"], "", log);
+        assert_eq!(i18n.parse_number("\u{2212}0,25"), Ok(-0.25));
     }
 
     #[test]
@@ -476,4 +2057,700 @@ mod test {
             "two args: 1 and 2,07"
         );
     }
+
+    #[test]
+    fn strict() {
+        let i18n = test_i18n(&["zz"]);
+
+        assert_eq!(
+            i18n.trn_strict_("two-args-key", tr_args!["one"=>1, "two"=>"2"]),
+            Ok("two args: 1 and 2".to_string())
+        );
+        assert!(i18n
+            .trn_strict_("two-args-key", tr_args!["one"=>1])
+            .is_err());
+        assert!(i18n.trn_strict_("invalid-key", tr_args![]).is_err());
+    }
+
+    #[test]
+    fn fingerprints() {
+        let en = test_i18n(&["zz"]);
+        let en2 = test_i18n(&["zz"]);
+        let ja = test_i18n(&["ja"]);
+
+        // identical content gives identical fingerprints
+        let fp = en.key_fingerprint_("valid-key");
+        assert!(fp.is_some());
+        assert_eq!(fp, en2.key_fingerprint_("valid-key"));
+
+        // but a different value changes it
+        assert_ne!(fp, ja.key_fingerprint_("valid-key"));
+        assert_ne!(fp, en.key_fingerprint_("only-in-english"));
+
+        assert_eq!(en.key_fingerprint_("invalid-key"), None);
+
+        // attributes are included
+        let fp = en.key_fingerprint_("with-voice");
+        en.apply_patch(None, "with-voice = Del\n    .voice = Remove")
+            .unwrap();
+        assert_ne!(fp, en.key_fingerprint_("with-voice"));
+    }
+
+    #[test]
+    fn message_sources() {
+        let i18n = test_i18n(&["zz"]);
+        let inner = i18n.inner.lock().unwrap();
+        let template = inner.bundles.last().unwrap();
+        let source = |key: &str| message_source(&template.get_message(key).unwrap());
+
+        assert_eq!(source("two-args-key"), "two args: {$one} and {$two}");
+        assert_eq!(source("with-voice"), "Del\n.voice = Delete");
+        assert_eq!(source("with-reference"), "Found {cards}.");
+    }
+
+    #[test]
+    fn folder() {
+        let i18n = test_i18n(&["ja"]);
+        assert!(i18n.check_folder().is_ok());
+        assert_eq!(i18n.locale_folder(), test_ftl_dir());
+
+        let i18n = I18n::new(&["ja"], test_ftl_dir().join("missing"), log::terminal());
+        assert!(i18n.check_folder().is_err());
+    }
+
+    #[test]
+    fn direction() {
+        let i18n = test_i18n(&["ar"]);
+        assert_eq!(i18n.text_direction(), CharacterDirection::RTL);
+        let (text, dir) = i18n.tr_with_dir(TR::CardStatsAdded);
+        assert!(!text.is_empty());
+        assert_eq!(dir, CharacterDirection::RTL);
+
+        let i18n = test_i18n(&["ja"]);
+        assert_eq!(i18n.text_direction(), CharacterDirection::LTR);
+    }
+
+    #[test]
+    fn reader() {
+        let i18n = test_i18n(&["zz"]);
+
+        let mut reader = Cursor::new("valid-key = from a reader".as_bytes());
+        i18n.add_resource_from_reader(&mut reader);
+        assert_eq!(i18n.tr_("valid-key", None), "from a reader");
+        assert_eq!(i18n.tr_("only-in-english", None), "not translated");
+
+        // unparseable text is ignored
+        let mut reader = Cursor::new("only-in-english = {".as_bytes());
+        i18n.add_resource_from_reader(&mut reader);
+        assert_eq!(i18n.tr_("only-in-english", None), "not translated");
+    }
+
+    #[test]
+    fn resolution() {
+        let i18n = test_i18n(&["ja"]);
+
+        assert_eq!(
+            i18n.resolution_chain_("valid-key"),
+            vec![Some(langid!("ja"))]
+        );
+        assert_eq!(
+            i18n.resolution_chain_("only-in-english"),
+            vec![Some(langid!("ja")), None]
+        );
+        assert_eq!(
+            i18n.resolution_chain_("invalid-key"),
+            vec![Some(langid!("ja")), None]
+        );
+    }
+
+    #[test]
+    fn context() {
+        let i18n = test_i18n(&["zz"]);
+
+        assert_eq!(i18n.tr_context_("with-voice", TrContext::Visual), "Del");
+        assert_eq!(i18n.tr_context_("with-voice", TrContext::Voice), "Delete");
+        // no attribute; uses value
+        assert_eq!(
+            i18n.tr_context_("valid-key", TrContext::Voice),
+            "a valid key"
+        );
+    }
+
+    #[test]
+    fn replacement_chars() {
+        let args = tr_args!["one"=>"ok", "two"=>"bad\u{FFFD}", "three"=>3];
+        assert_eq!(args_with_replacement_chars(&args), vec!["two"]);
+        assert!(args_with_replacement_chars(&tr_args!["one"=>"ok"]).is_empty());
+
+        let (i18n, logged) = test_i18n_with_log(&["zz"]);
+        i18n.tr_(
+            "two-args-key",
+            Some(tr_args!["one"=>"ok", "two"=>"bad\u{FFFD}"]),
+        );
+        assert_eq!(
+            logged.lock().unwrap().as_slice(),
+            &["Replacement character in arg(s) [\"two\"] of 'two-args-key'".to_string()]
+        );
+    }
+
+    #[test]
+    fn variables() {
+        let i18n = test_i18n(&["ja"]);
+
+        assert_eq!(i18n.keys_using_var("hats"), vec!["plural"]);
+        assert_eq!(i18n.keys_using_var("two"), vec!["two-args-key"]);
+        assert!(i18n.keys_using_var("missing").is_empty());
+    }
+
+    #[test]
+    fn per_call_fallback() {
+        let i18n = test_i18n(&["pl-PL"]);
+
+        // not in Polish, so normally comes from English
+        assert_eq!(i18n.tr_("valid-key", None), "a valid key");
+        assert_eq!(
+            i18n.trn_fallback_("valid-key", tr_args![], &langid!("ja")),
+            "キー"
+        );
+        // not in Japanese either
+        assert_eq!(
+            i18n.trn_fallback_("only-in-english", tr_args![], &langid!("ja")),
+            "not translated"
+        );
+    }
+
+    #[test]
+    fn unused() {
+        let (i18n, logged) = test_i18n_with_log(&["zz"]);
+        {
+            let inner = i18n.inner.lock().unwrap();
+            let template = inner.bundles.last().unwrap();
+            let msg = template.get_message("two-args-key").unwrap();
+            assert!(unused_args(template, &msg, &tr_args!["one"=>1, "two"=>2]).is_empty());
+            assert_eq!(
+                unused_args(template, &msg, &tr_args!["one"=>1, "two"=>2, "three"=>3]),
+                vec!["three"]
+            );
+        }
+
+        // the check in tr_() only happens in debug builds
+        i18n.tr_(
+            "two-args-key",
+            Some(tr_args!["one"=>1, "two"=>2, "three"=>3]),
+        );
+        if cfg!(debug_assertions) {
+            assert_eq!(
+                logged.lock().unwrap().as_slice(),
+                &["Unused arg(s) [\"three\"] passed to 'two-args-key'".to_string()]
+            );
+        }
+
+        // args only used by referenced messages are not unused
+        logged.lock().unwrap().clear();
+        let args = tr_args!["amount"=>1.5, "unit"=>"minutes", "cards"=>3, "secs-per-card"=>30];
+        i18n.tr_("statistics-studied-today", Some(args));
+        assert!(logged.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn patches() {
+        let i18n = test_i18n(&["ja"]);
+        let ja = langid!("ja");
+
+        assert!(i18n.apply_patch(Some(&ja), "valid-key = patched").is_ok());
+        assert_eq!(i18n.tr_("valid-key", None), "patched");
+        assert_eq!(i18n.tr_("only-in-english", None), "not translated");
+        assert!(i18n.resources_for_js().resources[0].starts_with("valid-key = patched\n"));
+
+        // invalid patches are not applied
+        assert!(i18n
+            .apply_patch(Some(&ja), "valid-key = ok\ntwo-args-key = {")
+            .is_err());
+        assert_eq!(i18n.tr_("valid-key", None), "patched");
+
+        // previews leave the messages unchanged
+        assert_eq!(
+            i18n.preview_patch(Some(&ja), "valid-key = proposed")
+                .unwrap(),
+            vec![(
+                "valid-key".to_string(),
+                "patched".to_string(),
+                "proposed".to_string()
+            )]
+        );
+        assert_eq!(i18n.tr_("valid-key", None), "patched");
+        assert!(i18n.preview_patch(Some(&ja), "valid-key = {").is_err());
+
+        // references resolve as they would once the patch was applied,
+        // including to earlier patches
+        assert_eq!(
+            i18n.preview_patch(Some(&ja), "new-key = { valid-key }!")
+                .unwrap(),
+            vec![(
+                "new-key".to_string(),
+                "new-key".to_string(),
+                "patched!".to_string()
+            )]
+        );
+
+        // patches add to the memory used, even when they replace a message
+        let size = i18n.memory_estimate();
+        i18n.apply_patch(Some(&ja), "new-key = some text").unwrap();
+        let size2 = i18n.memory_estimate();
+        assert!(size2 > size);
+        i18n.apply_patch(Some(&ja), "new-key = x").unwrap();
+        assert!(i18n.memory_estimate() > size2);
+
+        // the English template can be patched too, and is only used for
+        // messages the preferred language doesn't have
+        i18n.apply_patch(None, "valid-key = english\nonly-in-english = patched")
+            .unwrap();
+        assert_eq!(i18n.tr_("valid-key", None), "patched");
+        assert_eq!(i18n.tr_("only-in-english", None), "patched");
+        let resources = i18n.resources_for_js().resources;
+        assert!(resources
+            .last()
+            .unwrap()
+            .starts_with("valid-key = english\n"));
+        assert_eq!(
+            i18n.preview_patch(None, "only-in-english = proposed")
+                .unwrap(),
+            vec![(
+                "only-in-english".to_string(),
+                "patched".to_string(),
+                "proposed".to_string()
+            )]
+        );
+
+        // but languages without a bundle can't be
+        assert!(i18n
+            .apply_patch(Some(&langid!("fr")), "valid-key = x")
+            .is_err());
+    }
+
+    #[test]
+    fn count_noun() {
+        let i18n = test_i18n(&["zz"]);
+
+        assert_eq!(i18n.count_noun_("cards", "count", 1), "1 card");
+        assert_eq!(i18n.count_noun_("cards", "count", 5), "5 cards");
+
+        // selector on a different variable
+        assert_eq!(i18n.count_noun_("plural", "hats", 1), "You have 1 hat.");
+        assert_eq!(i18n.count_noun_("plural", "hats", 3), "You have 3 hats.");
+    }
+
+    #[test]
+    fn rejected() {
+        let i18n = test_i18n(&["eng!", "ja"]);
+        assert_eq!(i18n.rejected_codes(), vec!["eng!".to_string()]);
+        assert_eq!(i18n.tr_("valid-key", None), "キー");
+
+        // an empty code means no preference, and is not reported
+        let i18n = test_i18n(&[""]);
+        assert!(i18n.rejected_codes().is_empty());
+    }
+
+    #[test]
+    fn plural_categories() {
+        let i18n = test_i18n(&["zz"]);
+
+        assert_eq!(
+            i18n.trn_with_category_("plural", "hats", 1.0),
+            ("You have 1 hat.".to_string(), PluralCategory::ONE)
+        );
+        assert_eq!(
+            i18n.trn_with_category_("plural", "hats", 3.0),
+            ("You have 3 hats.".to_string(), PluralCategory::OTHER)
+        );
+    }
+
+    #[test]
+    fn variant_previews() {
+        let i18n = test_i18n(&["zz"]);
+
+        assert_eq!(
+            i18n.preview_variants_("plural", "hats"),
+            vec![
+                (PluralCategory::ONE, "You have 1 hat.".to_string()),
+                (PluralCategory::OTHER, "You have 0 hats.".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn number_options() {
+        let i18n = test_i18n(&["pl-PL"]);
+
+        // one decimal place for the first arg, and the default of two
+        // for the second
+        let num = FluentValue::Number(FluentNumber::new(
+            1.234,
+            FluentNumberOptions {
+                maximum_fraction_digits: Some(1),
+                ..Default::default()
+            },
+        ));
+        let mut args = tr_args!["two"=>1.234];
+        args.insert("one", num);
+        assert_eq!(
+            i18n.tr_("two-args-key", Some(args)),
+            "two args: 1,2 and 1,23"
+        );
+    }
+
+    #[test]
+    fn negotiation() {
+        assert_eq!(
+            I18n::explain_negotiation(&["ja_JP", "xx", "eng!", "en-GB", "de"]),
+            "requested ja_JP → translation for ja-JP
+requested xx → no translation, skipped
+requested eng! → could not be parsed, ignored
+requested en-GB → translation for en-GB
+any further languages are ignored
+anything untranslated will use English"
+        );
+    }
+
+    #[test]
+    fn lazy_args() {
+        let i18n = test_i18n(&["zz"]);
+
+        assert_eq!(
+            i18n.trn_lazy_("valid-key", || panic!("args not needed")),
+            "a valid key"
+        );
+        assert_eq!(
+            i18n.trn_lazy_("two-args-key", || tr_args!["one"=>1, "two"=>2]),
+            "two args: 1 and 2"
+        );
+        // only needs args through the message it references
+        assert_eq!(
+            i18n.trn_lazy_("with-reference", || tr_args!["count"=>2]),
+            "Found 2 cards."
+        );
+
+        // cyclic references don't prevent variables being found
+        i18n.apply_patch(None, "cycle-a = { cycle-b }\ncycle-b = { cycle-a } { $n }")
+            .unwrap();
+        assert_eq!(i18n.keys_using_var("n"), vec!["cycle-a", "cycle-b"]);
+    }
+
+    #[test]
+    fn counts() {
+        let i18n = test_i18n(&["zz"]);
+
+        assert_eq!(
+            i18n.trn_counts_("two-counts", &[("cards", 1), ("notes", 3)]),
+            "1 card and 3 notes"
+        );
+        assert_eq!(
+            i18n.trn_counts_("two-counts", &[("cards", 2), ("notes", 1)]),
+            "2 cards and 1 note"
+        );
+    }
+
+    #[test]
+    fn variant_keys() {
+        let i18n = test_i18n(&["ja"]);
+        // the Japanese fixture has renamed [other]
+        assert!(i18n
+            .invalid_variant_keys()
+            .contains(&("plural".to_string(), "others".to_string())));
+
+        let i18n = test_i18n(&["zz"]);
+        assert!(i18n.invalid_variant_keys().is_empty());
+    }
+
+    #[test]
+    fn term_references() {
+        let mut ftl_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        ftl_dir.push("tests/support/ftl");
+
+        let log = log::terminal();
+        let i18n = I18n::new(&["zz"], &ftl_dir, log.clone());
+        assert_eq!(i18n.tr_("uses-term", None), "Welcome to Anki.");
+        let missing = ("missing-term".to_string(), "missing-site".to_string());
+        let brand = ("uses-term".to_string(), "brand".to_string());
+        let unresolved = i18n.unresolved_term_references();
+        assert!(unresolved.contains(&missing));
+        assert!(!unresolved.contains(&brand));
+
+        // the Japanese fixture uses -brand without defining it
+        let i18n = I18n::new(&["ja"], &ftl_dir, log);
+        let unresolved = i18n.unresolved_term_references();
+        assert!(unresolved.contains(&missing));
+        assert!(unresolved.contains(&brand));
+
+        // a patch can supply it
+        i18n.apply_patch(Some(&langid!("ja")), "-brand = Anki")
+            .unwrap();
+        assert!(!i18n.unresolved_term_references().contains(&brand));
+    }
+
+    #[test]
+    fn reset() {
+        let mut ftl_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        ftl_dir.push("tests/support/ftl");
+
+        let i18n = I18n::new(&["eng!", "ja"], &ftl_dir, log::terminal());
+        i18n.set_number_grouping(true);
+        i18n.apply_patch(Some(&langid!("ja")), "valid-key = patched")
+            .unwrap();
+        assert_eq!(i18n.tr_("valid-key", None), "patched");
+        i18n.set_trace(Some(Box::new(|_| panic!("trace not cleared"))));
+
+        i18n.reset();
+        assert_eq!(i18n.tr_("valid-key", None), "キー");
+        assert_eq!(
+            i18n.tr_("plural", Some(tr_args!["hats"=>1234.0])),
+            "帽子1234個"
+        );
+        assert_eq!(i18n.inner.lock().unwrap().langs[0], langid!("ja"));
+        assert_eq!(i18n.rejected_codes(), vec!["eng!".to_string()]);
+
+        // extensions are dropped when parsing, but still reported
+        let i18n = test_i18n(&["ja-JP-u-ca-japanese"]);
+        i18n.reset();
+        assert_eq!(i18n.locale_tags()[0], "ja-JP-u-ca-japanese");
+    }
+
+    #[test]
+    fn chinese_scripts() {
+        let traditional = ftl_localized_text(&langid!("zh-TW"));
+        let simplified = ftl_localized_text(&langid!("zh-CN"));
+
+        assert_eq!(ftl_localized_text(&langid!("zh-HK")), traditional);
+        assert_eq!(ftl_localized_text(&langid!("zh")), simplified);
+
+        // script overrides region
+        assert_eq!(ftl_localized_text(&langid!("zh-Hant")), traditional);
+        assert_eq!(ftl_localized_text(&langid!("zh-Hant-CN")), traditional);
+        assert_eq!(ftl_localized_text(&langid!("zh-Hans-HK")), simplified);
+
+        // and the same rule picks the folder
+        let folder = |lang| lang_folder(Some(&lang), &test_ftl_dir());
+        let traditional = Some(test_ftl_dir().join("zh_TW"));
+        let simplified = Some(test_ftl_dir().join("zh_CN"));
+        assert_eq!(folder(langid!("zh-TW")), traditional);
+        assert_eq!(folder(langid!("zh-HK")), traditional);
+        assert_eq!(folder(langid!("zh")), simplified);
+        assert_eq!(folder(langid!("zh-Hant")), traditional);
+        assert_eq!(folder(langid!("zh-Hant-CN")), traditional);
+        assert_eq!(folder(langid!("zh-Hans-HK")), simplified);
+        assert_eq!(
+            test_i18n(&["zh-Hant-CN"]).tr_("valid-key", None),
+            "有效的鍵"
+        );
+    }
+
+    #[test]
+    fn extensions() {
+        // Unicode extensions are ignored when picking a language
+        let i18n = test_i18n(&["ja-JP-u-ca-japanese"]);
+        assert_eq!(i18n.tr_("valid-key", None), "キー");
+        assert_eq!(i18n.inner.lock().unwrap().langs[0], langid!("ja-JP"));
+        // but kept for other uses
+        assert_eq!(
+            i18n.locale_tags(),
+            vec!["ja-JP-u-ca-japanese".to_string(), "en-US".to_string()]
+        );
+
+        // as are private use ones
+        let i18n = test_i18n(&["en-US-x-dev"]);
+        assert_eq!(i18n.tr_("valid-key", None), "a valid key");
+        assert_eq!(i18n.inner.lock().unwrap().langs[0], langid!("en-US"));
+        assert_eq!(
+            i18n.locale_tags(),
+            vec!["en-US-x-dev".to_string(), "en-US".to_string()]
+        );
+    }
+
+    /// Format every message with random values for its variables, checking
+    /// that formatting doesn't panic and the output stays a sane length.
+    fn fuzz_messages(i18n: &I18n, rng: &mut StdRng) {
+        let messages: Vec<(String, Vec<String>)> = {
+            let inner = i18n.inner.lock().unwrap();
+            inner
+                .keys
+                .iter()
+                .map(|key| {
+                    let mut vars: Vec<_> = inner
+                        .bundles
+                        .iter()
+                        .filter_map(|bundle| bundle.get_message(key).map(|msg| (bundle, msg)))
+                        .flat_map(|(bundle, msg)| message_variables(bundle, &msg))
+                        .map(ToString::to_string)
+                        .collect();
+                    vars.sort();
+                    vars.dedup();
+                    (key.clone(), vars)
+                })
+                .collect()
+        };
+
+        for (key, vars) in &messages {
+            for _ in 0..10 {
+                let mut args = FluentArgs::new();
+                for var in vars {
+                    let val: FluentValue = match rng.gen_range(0, 5) {
+                        0 => rng.gen_range(-1000, 1000).into(),
+                        1 => rng.gen_range(-1000.0, 1000.0).into(),
+                        2 => "".into(),
+                        3 => "text <b>&amp;</b> {$x}".into(),
+                        _ => 0.into(),
+                    };
+                    args.insert(var.as_str(), val);
+                }
+                let out = i18n.tr_(key, Some(args));
+                assert!(out.len() < 10_000, "{}: {}", key, out);
+            }
+        }
+    }
+
+    #[test]
+    fn fuzzed_args() {
+        let mut rng = StdRng::seed_from_u64(0);
+
+        for &lang in &["zz", "ja", "zh-TW", "ar"] {
+            let i18n = test_i18n(&[lang]);
+            fuzz_messages(&i18n, &mut rng);
+        }
+    }
+
+    #[test]
+    fn empty_fallbacks() {
+        let i18n = test_i18n(&["ja"]);
+
+        assert_eq!(
+            i18n.empty_fallback_keys(),
+            vec!["empty-value".to_string(), "no-value".to_string()]
+        );
+    }
+
+    #[test]
+    fn translation_memory() {
+        let i18n = test_i18n(&["zz"]);
+        let pairs = i18n.export_translation_memory(&langid!("ja"));
+        let pair = |key: &str| pairs.iter().find(|(k, _, _)| k == key).cloned();
+
+        assert_eq!(
+            pair("valid-key"),
+            Some((
+                "valid-key".to_string(),
+                "a valid key".to_string(),
+                "キー".to_string()
+            ))
+        );
+        assert_eq!(
+            pair("two-args-key"),
+            Some((
+                "two-args-key".to_string(),
+                "two args: {$one} and {$two}".to_string(),
+                "{$one}と{$two}".to_string()
+            ))
+        );
+        // untranslated keys are skipped
+        assert_eq!(pair("only-in-english"), None);
+
+        // languages without a translation have nothing to export
+        assert!(i18n.export_translation_memory(&langid!("zz")).is_empty());
+    }
+
+    #[test]
+    fn html_args() {
+        let i18n = test_i18n(&["zz"]);
+
+        assert_eq!(
+            i18n.trn_html_("two-args-key", tr_args!["one"=>"<b>", "two"=>"a & b"]),
+            "two args: &lt;b&gt; and a &amp; b"
+        );
+        // numbers are left alone
+        assert_eq!(
+            i18n.trn_html_("two-args-key", tr_args!["one"=>1, "two"=>"2"]),
+            "two args: 1 and 2"
+        );
+    }
+
+    #[test]
+    fn no_fallback() {
+        let i18n = test_i18n(&["ja"]);
+        assert_eq!(i18n.tr_no_fallback_("valid-key"), Some("キー".to_string()));
+        assert_eq!(i18n.tr_no_fallback_("only-in-english"), None);
+
+        // English users have no localized bundles
+        let i18n = test_i18n(&["en"]);
+        assert_eq!(i18n.tr_no_fallback_("valid-key"), None);
+    }
+
+    #[test]
+    fn quotes() {
+        let i18n = test_i18n(&["zz"]);
+        assert_eq!(i18n.quote("deck"), "“deck”");
+        assert_eq!(i18n.quote_nested("deck"), "‘deck’");
+
+        let i18n = test_i18n(&["fr-CA"]);
+        assert_eq!(i18n.quote("deck"), "«deck»");
+
+        let i18n = test_i18n(&["ja"]);
+        assert_eq!(i18n.quote("デッキ"), "「デッキ」");
+        assert_eq!(i18n.quote_nested("デッキ"), "『デッキ』");
+
+        // Chinese marks follow the script, like the translations
+        let i18n = test_i18n(&["zh-Hant-CN"]);
+        assert_eq!(i18n.quote("牌組"), "「牌組」");
+        assert_eq!(i18n.quote_nested("牌組"), "『牌組』");
+        let i18n = test_i18n(&["zh-TW"]);
+        assert_eq!(i18n.quote("牌組"), "「牌組」");
+        let i18n = test_i18n(&["zh-Hans-HK"]);
+        assert_eq!(i18n.quote("牌组"), "“牌组”");
+    }
+
+    #[test]
+    fn try_new() {
+        let log = log::terminal();
+        let ftl_dir = test_ftl_dir();
+        assert!(I18n::try_new(&["ja"], &ftl_dir, log.clone()).is_ok());
+        assert!(I18n::try_new(&["!!"], &ftl_dir, log.clone()).is_ok());
+
+        let missing = ftl_dir.join("missing");
+        // unknown languages still fall back on English
+        assert!(I18n::try_new(&["zz"], &missing, log.clone()).is_ok());
+        // as does an empty code, which means no preference
+        assert!(I18n::try_new(&[""], &missing, log.clone()).is_ok());
+        assert!(I18n::try_new(&["!!", "1"], &missing, log).is_err());
+    }
+
+    #[test]
+    fn trace() {
+        let i18n = test_i18n(&["ja"]);
+        let events = Arc::new(Mutex::new(vec![]));
+        let events2 = events.clone();
+        i18n.set_trace(Some(Box::new(move |event| {
+            events2.lock().unwrap().push(event)
+        })));
+
+        i18n.tr_("valid-key", None);
+        i18n.tr_("only-in-english", None);
+        i18n.tr_("two-args-key", Some(tr_args!["one"=>1]));
+        i18n.tr_("invalid-key", None);
+        i18n.set_trace(None);
+        i18n.tr_("valid-key", None);
+
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 4);
+        assert_eq!(
+            events[0],
+            TraceEvent {
+                key: "valid-key".into(),
+                lang: Some(langid!("ja")),
+                fallback: false,
+                found: true,
+                errors: vec![],
+            }
+        );
+        assert_eq!((events[1].lang.clone(), events[1].fallback), (None, true));
+        assert_eq!(events[2].errors.len(), 1);
+        assert!(!events[3].found);
+    }
 }
@@ -2,6 +2,7 @@
 // License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
 
 use crate::i18n::{tr_args, I18n, TR};
+use itertools::Itertools;
 
 /// Short string like '4d' to place above answer buttons.
 pub fn answer_button_time(seconds: f32, i18n: &I18n) -> String {
@@ -41,6 +42,52 @@ pub fn time_span(seconds: f32, i18n: &I18n, precise: bool) -> String {
     i18n.trn(key, args)
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DurationStyle {
+    /// eg "2h 30m"
+    Short,
+    /// eg "2 hours 30 minutes"
+    Long,
+}
+
+/// Describe an elapsed time in hours and minutes, eg 9030 seconds ->
+/// "2h 30m" or "2 hours 30 minutes". Times under a minute are shown
+/// in seconds, and zero as "0m".
+pub fn duration(seconds: i64, style: DurationStyle, i18n: &I18n) -> String {
+    let seconds = seconds.max(0);
+    let hours = seconds / 3600;
+    let minutes = (seconds % 3600) / 60;
+
+    let mut parts = vec![];
+    if seconds > 0 && seconds < 60 {
+        parts.push((TimespanUnit::Seconds, seconds));
+    } else {
+        if hours > 0 {
+            parts.push((TimespanUnit::Hours, hours));
+        }
+        if minutes > 0 || hours == 0 {
+            parts.push((TimespanUnit::Minutes, minutes));
+        }
+    }
+
+    parts
+        .into_iter()
+        .map(|(unit, amount)| {
+            let key = match (style, unit) {
+                (DurationStyle::Short, TimespanUnit::Seconds) => {
+                    TR::SchedulingAnswerButtonTimeSeconds
+                }
+                (DurationStyle::Short, TimespanUnit::Hours) => TR::SchedulingAnswerButtonTimeHours,
+                (DurationStyle::Short, _) => TR::SchedulingAnswerButtonTimeMinutes,
+                (DurationStyle::Long, TimespanUnit::Seconds) => TR::SchedulingTimeSpanSeconds,
+                (DurationStyle::Long, TimespanUnit::Hours) => TR::SchedulingTimeSpanHours,
+                (DurationStyle::Long, _) => TR::SchedulingTimeSpanMinutes,
+            };
+            i18n.trn(key, tr_args!["amount" => amount])
+        })
+        .join(" ")
+}
+
 // fixme: this doesn't belong here
 pub fn studied_today(cards: usize, secs: f32, i18n: &I18n) -> String {
     let span = Timespan::from_secs(secs).natural_span();
@@ -178,7 +225,8 @@ mod test {
     use crate::i18n::I18n;
     use crate::log;
     use crate::sched::timespan::{
-        answer_button_time, learning_congrats, studied_today, time_span, MONTH,
+        answer_button_time, duration, learning_congrats, studied_today, time_span, DurationStyle,
+        MONTH,
     };
 
     #[test]
@@ -202,6 +250,21 @@ mod test {
         assert_eq!(time_span(365.0 * 86_400.0 * 1.5, &i18n, false), "1.5 years");
     }
 
+    #[test]
+    fn durations() {
+        let log = log::terminal();
+        let i18n = I18n::new(&["zz"], "", log);
+        assert_eq!(duration(9030, DurationStyle::Short, &i18n), "2h 30m");
+        assert_eq!(
+            duration(9030, DurationStyle::Long, &i18n),
+            "2 hours 30 minutes"
+        );
+        assert_eq!(duration(3600, DurationStyle::Long, &i18n), "1 hour");
+        assert_eq!(duration(45, DurationStyle::Short, &i18n), "45s");
+        assert_eq!(duration(0, DurationStyle::Short, &i18n), "0m");
+        assert_eq!(duration(0, DurationStyle::Long, &i18n), "0 minutes");
+    }
+
     #[test]
     fn combo() {
         // temporary test of fluent term handling
@@ -1,2 +1,6 @@
 valid-key = キー
 two-args-key = {$one}と{$two}
+plural = {$hats ->
+    *[others] 帽子{$hats}個
+  }
+uses-term = {-brand}へようこそ。
@@ -5,3 +5,23 @@ plural = You have {$hats ->
      [one]   1 hat
     *[other] {$hats} hats
   }.
+with-voice = Del
+    .voice = Delete
+cards = {$count ->
+     [one]   {$count} card
+    *[other] {$count} cards
+  }
+two-counts = {$cards ->
+     [one]   1 card
+    *[other] {$cards} cards
+  } and {$notes ->
+     [one]   1 note
+    *[other] {$notes} notes
+  }
+empty-value = { "" }
+no-value =
+    .voice = Spoken only
+with-reference = Found { cards }.
+-brand = Anki
+uses-term = Welcome to {-brand}.
+missing-term = See {-missing-site}.
@@ -0,0 +1 @@
+valid-key = 有效的鍵